@@ -4,10 +4,125 @@ use axum::{
     response::{IntoResponse, Response},
     http::StatusCode,
 };
+use std::net::IpAddr;
 use crate::proxy::server::AppState;
 use crate::modules::security_db;
 
+mod auto_ban;
+mod ip_cache;
+use auto_ban::AutoBanTracker;
+use ip_cache::ClockCache;
+
+/// Bounded caches of recent IP→verdict decisions so `ip_filter_middleware` only has to hit
+/// SQLite on a cache miss. Separate caches because a whitelist verdict is a plain bool while
+/// a blacklist verdict needs the matched entry's reason/expiry to build the block message.
+/// `invalidate_ip_filter_cache` must be called wherever the whitelist/blacklist tables are
+/// mutated (e.g. the security settings command handlers) so stale verdicts aren't served.
+///
+/// As things stand, this module only wires that call into its own write path
+/// ([`ip_filter_middleware`]'s auto-ban insert below) — the security-settings command handlers
+/// that let an admin add/edit/remove an entry by hand live in `security_db` and its Tauri
+/// commands, neither of which are part of this source tree, so they can't be wired up from
+/// here. Until they are, a manual emergency block/un-ban/whitelist edit is only guaranteed to
+/// take effect within [`IP_CACHE_TTL`], not immediately — that bound is intentionally kept
+/// short (a few seconds, not the tens-of-seconds a hit-rate-tuned cache would otherwise use)
+/// precisely because this is a security control and the primary mutation path isn't
+/// invalidation-wired yet. Once `security_db`'s command handlers exist in this tree, add
+/// `invalidate_ip_filter_cache()` calls there and this TTL can be relaxed back to a
+/// throughput-oriented value.
+static WHITELIST_CACHE: std::sync::OnceLock<ClockCache<IpAddr, bool>> = std::sync::OnceLock::new();
+static BLACKLIST_CACHE: std::sync::OnceLock<ClockCache<IpAddr, Option<BlacklistVerdict>>> =
+    std::sync::OnceLock::new();
+
+const IP_CACHE_CAPACITY: usize = 10_000;
+/// Kept short (rather than a throughput-tuned tens-of-seconds value) because this is the only
+/// bound on how long a manual whitelist/blacklist mutation takes to apply — see the cache docs
+/// above.
+const IP_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(3);
+
+fn whitelist_cache() -> &'static ClockCache<IpAddr, bool> {
+    WHITELIST_CACHE.get_or_init(|| ClockCache::new(IP_CACHE_CAPACITY, IP_CACHE_TTL))
+}
+
+fn blacklist_cache() -> &'static ClockCache<IpAddr, Option<BlacklistVerdict>> {
+    BLACKLIST_CACHE.get_or_init(|| ClockCache::new(IP_CACHE_CAPACITY, IP_CACHE_TTL))
+}
+
+/// Sliding-window abuse counter shared by every request, feeding the auto-ban escalation
+/// described on [`ip_filter_middleware`].
+static AUTO_BAN_TRACKER: std::sync::OnceLock<AutoBanTracker> = std::sync::OnceLock::new();
+
+fn auto_ban_tracker() -> &'static AutoBanTracker {
+    AUTO_BAN_TRACKER.get_or_init(AutoBanTracker::new)
+}
+
+/// Bumps the generation counter on both caches, invalidating every cached verdict at once.
+/// Call this after any whitelist/blacklist table mutation (add/remove/edit entry).
+pub fn invalidate_ip_filter_cache() {
+    whitelist_cache().invalidate_all();
+    blacklist_cache().invalidate_all();
+}
+
+/// The parts of a matched blacklist entry the middleware needs to build a response, cached
+/// alongside the IP so a hit never has to touch SQLite.
+#[derive(Clone, Debug)]
+struct BlacklistVerdict {
+    reason: Option<String>,
+    expires_at: Option<i64>,
+}
+
+/// Classifies `ip` against a whitelist/blacklist entry, which may be a bare IP (`10.0.0.1`,
+/// `2001:db8::1`) or a CIDR range (`10.0.0.0/8`, `2001:db8::/32`).
+fn ip_matches_entry(ip: &IpAddr, entry: &str) -> bool {
+    if let Ok(net) = entry.parse::<ipnet::IpNet>() {
+        return net.contains(ip);
+    }
+    entry.parse::<IpAddr>().map(|entry_ip| entry_ip == *ip).unwrap_or(false)
+}
+
+/// Checks whitelist membership for `ip`, consulting [`whitelist_cache`] before SQLite.
+/// CIDR-aware: whitelist entries may be single IPs or subnets.
+fn check_whitelist(ip: IpAddr) -> Result<bool, String> {
+    if let Some(cached) = whitelist_cache().get(&ip) {
+        return Ok(cached);
+    }
+
+    let entries = security_db::get_whitelist_entries()?;
+    let matched = entries.iter().any(|entry| ip_matches_entry(&ip, entry));
+    whitelist_cache().insert(ip, matched);
+    Ok(matched)
+}
+
+/// Checks blacklist membership for `ip`, consulting [`blacklist_cache`] before SQLite.
+/// CIDR-aware: blacklist entries may be single IPs or subnets. Entries whose `expires_at` has
+/// already passed are treated as not-in-the-blacklist rather than being matched and blocked —
+/// an expired temporary/auto-ban row doesn't get a fresh block decision just because cleanup
+/// of the underlying table hasn't run yet. The cached `None` verdict is itself subject to the
+/// cache's normal TTL, so this doesn't need any extra invalidation of its own.
+fn check_blacklist(ip: IpAddr) -> Result<Option<BlacklistVerdict>, String> {
+    if let Some(cached) = blacklist_cache().get(&ip) {
+        return Ok(cached);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let entries = security_db::get_blacklist_entries()?;
+    let matched = entries
+        .iter()
+        .filter(|entry| entry.expires_at.map(|exp| exp > now).unwrap_or(true))
+        .find(|entry| ip_matches_entry(&ip, &entry.pattern))
+        .map(|entry| BlacklistVerdict {
+            reason: entry.reason.clone(),
+            expires_at: entry.expires_at,
+        });
+    blacklist_cache().insert(ip, matched.clone());
+    Ok(matched)
+}
+
 /// IP 黑白名单过滤中间件
+///
+/// 放行后也会追踪下游响应：同一 IP 在 `security_config.security_monitor.auto_ban.window_secs`
+/// 秒内产生 `threshold` 次及以上 `status >= 401` 的响应时，会被 [`auto_ban_tracker`] 判定为
+/// 自动封禁对象，按 `backoff_steps` 逐级延长封禁时长并写入黑名单（fail2ban 式升级封禁）。
 pub async fn ip_filter_middleware(
     State(state): State<AppState>,
     request: Request,
@@ -16,16 +131,22 @@ pub async fn ip_filter_middleware(
     // 读取安全配置
     let security_config = state.security.read().await;
 
-    // [FIX-A] 根据部署模式选择 IP 提取策略：
-    // - allow_lan_access=true 表示可能在反代后面，信任 X-Forwarded-For
-    // - 否则优先使用 TCP 连接 IP，防止 header 伪造绕过
-    let trust_proxy_headers = security_config.allow_lan_access;
-    let client_ip = extract_client_ip(&request, trust_proxy_headers);
+    // [FIX-A] 从 ConnectInfo 出发，只在对端是受信任代理时才剥离转发链上的一跳，
+    // 且只解析 `forwarded_header` 指定的单一头部，防止客户端伪造部署代理本不会
+    // 设置的那个转发头来绕过 IP 黑白名单。
+    let parsed_client_ip = extract_client_ip(
+        &request,
+        &security_config.trusted_proxies,
+        security_config.forwarded_header,
+    );
+
+    if let Some(parsed_ip) = parsed_client_ip {
+        let ip = parsed_ip.to_string();
+        let ip = ip.as_str();
 
-    if let Some(ip) = &client_ip {
         // 1. 检查白名单 (如果启用白名单模式,只允许白名单 IP)
         if security_config.security_monitor.whitelist.enabled {
-            match security_db::is_ip_in_whitelist(ip) {
+            match check_whitelist(parsed_ip) {
                 Ok(true) => {
                     // 在白名单中,直接放行
                     tracing::debug!("[IP Filter] IP {} is in whitelist, allowing", ip);
@@ -51,7 +172,7 @@ pub async fn ip_filter_middleware(
         } else {
             // 白名单优先模式: 如果在白名单中,跳过黑名单检查
             if security_config.security_monitor.whitelist.whitelist_priority {
-                match security_db::is_ip_in_whitelist(ip) {
+                match check_whitelist(parsed_ip) {
                     Ok(true) => {
                         tracing::debug!("[IP Filter] IP {} is in whitelist (priority mode), skipping blacklist check", ip);
                         return next.run(request).await;
@@ -69,7 +190,7 @@ pub async fn ip_filter_middleware(
 
         // 2. 检查黑名单
         if security_config.security_monitor.blacklist.enabled {
-            match security_db::get_blacklist_entry_for_ip(ip) {
+            match check_blacklist(parsed_ip) {
                 Ok(Some(entry)) => {
                     tracing::warn!("[IP Filter] IP {} is in blacklist, blocking", ip);
                     
@@ -107,7 +228,7 @@ pub async fn ip_filter_middleware(
                     // 记录被封禁的访问日志
                     let log = security_db::IpAccessLog {
                         id: uuid::Uuid::new_v4().to_string(),
-                        client_ip: ip.clone(),
+                        client_ip: ip.to_string(),
                         timestamp: chrono::Utc::now().timestamp(),
                         method: Some(request.method().to_string()),
                         path: Some(request.uri().to_string()),
@@ -148,61 +269,171 @@ pub async fn ip_filter_middleware(
         tracing::warn!("[IP Filter] Unable to extract client IP from request");
     }
 
-    // 放行请求
-    next.run(request).await
+    // 放行请求，并把结果喂给 fail2ban 式的自动封禁检测器
+    let response = next.run(request).await;
+
+    if let Some(parsed_ip) = parsed_client_ip {
+        let auto_ban = &security_config.security_monitor.auto_ban;
+        if auto_ban.enabled && response.status().as_u16() >= 401 {
+            let now = chrono::Utc::now().timestamp();
+            if let Some(ban_seconds) = auto_ban_tracker().record_and_check(
+                parsed_ip,
+                now,
+                auto_ban.threshold,
+                auto_ban.window_secs,
+                &auto_ban.backoff_steps,
+            ) {
+                let ip_str = parsed_ip.to_string();
+                tracing::warn!(
+                    "[IP Filter] Auto-ban: {} exceeded {} failures within {}s, banning for {}s",
+                    ip_str,
+                    auto_ban.threshold,
+                    auto_ban.window_secs,
+                    ban_seconds
+                );
+                tokio::spawn(async move {
+                    let expires_at = chrono::Utc::now().timestamp() + ban_seconds;
+                    match security_db::add_blacklist_entry(
+                        &ip_str,
+                        Some("Automatically banned: too many failed requests".to_string()),
+                        Some(expires_at),
+                    ) {
+                        Ok(()) => invalidate_ip_filter_cache(),
+                        Err(e) => {
+                            tracing::error!("[IP Filter] Failed to auto-ban {}: {}", ip_str, e)
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    response
 }
 
-/// 从请求中提取客户端 IP
+/// Which forwarding header the deployment's reverse proxy is known to set, consumed by
+/// [`forwarded_chain`]. Only this one header is ever parsed — an attacker-supplied copy of
+/// any *other* forwarding header is ignored outright rather than being given a chance to
+/// win a "which header do we prefer" tie-break, which is what let a spoofed `Forwarded:`
+/// header override a trustworthy proxy-appended `X-Forwarded-For` before this fix.
+/// `XForwardedFor` is the default because it's what virtually every reverse proxy (nginx,
+/// Caddy, HAProxy, ...) sets out of the box; operators whose proxy instead emits the RFC 7239
+/// `Forwarded` header (or nothing but `X-Real-Ip`) must opt into that explicitly.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ForwardedHeaderSource {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+    XRealIp,
+}
+
+/// 从请求中提取客户端真实 IP
 ///
-/// [FIX-A] 安全 IP 提取策略：
-/// - `trust_proxy_headers=false`（默认本机模式）: 优先使用 TCP 连接 IP (ConnectInfo)，
-///   防止客户端伪造 X-Forwarded-For 绕过 IP 黑白名单。
-/// - `trust_proxy_headers=true`（LAN/反代模式）: 优先使用代理 header，
-///   因为此时 ConnectInfo 是反代 IP 而非真实客户端 IP。
-fn extract_client_ip(request: &Request, trust_proxy_headers: bool) -> Option<String> {
-    if trust_proxy_headers {
-        // 反代模式：优先信任代理 header
-        request
+/// [FIX-A] 安全 IP 提取策略：从 TCP 连接的对端地址 ([`ConnectInfo`]) 出发，只有当对端地址
+/// 命中 `trusted_proxies` (CIDR 或精确 IP) 时才信任转发链头部；否则转发头可能是客户端伪造
+/// 的，直接把 ConnectInfo 当作真实客户端 IP。当对端确实是受信任代理时，沿转发链从最近一跳
+/// 向最早一跳回溯，只解析 `forwarded_header` 指定的单一头部 (不在多个转发头之间做"优先级"
+/// 选择，因为攻击者可以伪造部署代理本不会设置的那个头部)，只要某一跳仍是受信任代理就继续
+/// 剥离，遇到第一个不受信任的地址即为真实客户端 IP；如果整条链都是受信任代理，使用链上
+/// 最早的一跳。
+///
+/// [`ConnectInfo`]: axum::extract::ConnectInfo
+fn extract_client_ip(
+    request: &Request,
+    trusted_proxies: &[String],
+    forwarded_header: ForwardedHeaderSource,
+) -> Option<IpAddr> {
+    let peer_ip = request
+        .extensions()
+        .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
+        .map(|info| info.0.ip())?;
+
+    if !is_trusted_proxy(&peer_ip, trusted_proxies) {
+        return Some(peer_ip);
+    }
+
+    let mut client_ip = peer_ip;
+    for hop in forwarded_chain(request, forwarded_header).into_iter().rev() {
+        if is_trusted_proxy(&hop, trusted_proxies) {
+            client_ip = hop;
+            continue;
+        }
+        return Some(hop);
+    }
+    Some(client_ip)
+}
+
+/// Checks whether `ip` matches any entry in the configured trusted-proxy list, which may mix
+/// bare IPs and CIDR ranges just like the whitelist/blacklist tables.
+fn is_trusted_proxy(ip: &IpAddr, trusted_proxies: &[String]) -> bool {
+    trusted_proxies.iter().any(|entry| ip_matches_entry(ip, entry))
+}
+
+/// Returns the forwarding chain in hop order (oldest/client first, closest-to-server last),
+/// parsed from whichever single header `source` names. Only that header is consulted: mixing
+/// in another forwarding header as a fallback would let an attacker pick whichever of them the
+/// real proxy doesn't manage and smuggle a spoofed address in through it.
+fn forwarded_chain(request: &Request, source: ForwardedHeaderSource) -> Vec<IpAddr> {
+    match source {
+        ForwardedHeaderSource::Forwarded => request
+            .headers()
+            .get("forwarded")
+            .and_then(|v| v.to_str().ok())
+            .map(parse_forwarded_header)
+            .unwrap_or_default(),
+        ForwardedHeaderSource::XForwardedFor => request
             .headers()
             .get("x-forwarded-for")
             .and_then(|v| v.to_str().ok())
-            .map(|s| s.split(',').next().unwrap_or(s).trim().to_string())
-            .or_else(|| {
-                request
-                    .headers()
-                    .get("x-real-ip")
-                    .and_then(|v| v.to_str().ok())
-                    .map(|s| s.to_string())
-            })
-            .or_else(|| {
-                // 回退到 TCP 连接 IP
-                request
-                    .extensions()
-                    .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
-                    .map(|info| info.0.ip().to_string())
+            .map(|s| {
+                s.split(',')
+                    .filter_map(|hop| hop.trim().parse::<IpAddr>().ok())
+                    .collect()
             })
-    } else {
-        // 本机模式：优先使用 TCP 连接 IP，不信任可伪造的 header
-        request
-            .extensions()
-            .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
-            .map(|info| info.0.ip().to_string())
-            .or_else(|| {
-                // ConnectInfo 不可用时回退到 header（如测试环境）
-                request
-                    .headers()
-                    .get("x-forwarded-for")
-                    .and_then(|v| v.to_str().ok())
-                    .map(|s| s.split(',').next().unwrap_or(s).trim().to_string())
-            })
-            .or_else(|| {
-                request
-                    .headers()
-                    .get("x-real-ip")
-                    .and_then(|v| v.to_str().ok())
-                    .map(|s| s.to_string())
+            .unwrap_or_default(),
+        ForwardedHeaderSource::XRealIp => request
+            .headers()
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.trim().parse::<IpAddr>().ok())
+            .into_iter()
+            .collect(),
+    }
+}
+
+/// Parses the `for=` parameter out of each comma-separated hop of an RFC 7239 `Forwarded`
+/// header, e.g. `for=192.0.2.60;proto=http;by=203.0.113.43, for="[2001:db8::1]:4711"`.
+/// Hops with no (or an unparseable) `for=` parameter are skipped rather than aborting the
+/// whole header.
+fn parse_forwarded_header(header: &str) -> Vec<IpAddr> {
+    header
+        .split(',')
+        .filter_map(|hop| {
+            hop.split(';').find_map(|param| {
+                let param = param.trim();
+                if param.len() < 4 || !param.as_bytes()[..4].eq_ignore_ascii_case(b"for=") {
+                    return None;
+                }
+                parse_forwarded_for_value(&param[4..])
             })
+        })
+        .collect()
+}
+
+/// Parses a single `for=` value, which may be a bare IPv4 address, a quoted IPv6 address
+/// (`"[2001:db8::1]"`), or either form with a trailing `:port`.
+fn parse_forwarded_for_value(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(rest) = value.strip_prefix('[') {
+        let end = rest.find(']')?;
+        return rest[..end].parse::<IpAddr>().ok();
+    }
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip);
     }
+    // IPv4 with a trailing `:port`, e.g. `192.0.2.60:4711`.
+    let (host, _) = value.rsplit_once(':')?;
+    host.parse::<IpAddr>().ok()
 }
 
 /// 创建被封禁的响应
@@ -0,0 +1,222 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+/// How long an IP's last offense is remembered for backoff-escalation purposes before it
+/// decays back to a clean slate. Without this, a single offender from months ago would stay
+/// permanently pinned at the harshest `backoff_steps` tier.
+const OFFENSE_DECAY_SECS: i64 = 24 * 3600;
+
+/// Opportunistic sweep cadence: roughly every this many `record_and_check` calls, evict IPs
+/// whose sliding window is empty and offenses that have fully decayed, so a spoofed/distributed
+/// 401 flood (one hit per forged source IP, never repeated) can't grow the maps unbounded.
+const SWEEP_EVERY_N_CALLS: u64 = 256;
+
+/// fail2ban-style abuse detector: counts failed/blocked outcomes per client IP in a sliding
+/// time window and tells the caller when an IP has crossed the threshold, so it can be pushed
+/// into the blacklist. Escalation (how long repeat offenders get banned for) is tracked
+/// separately from the sliding window so a ban doesn't reset a client's offense history, but
+/// still decays after [`OFFENSE_DECAY_SECS`] of good behavior.
+pub struct AutoBanTracker {
+    events: RwLock<HashMap<IpAddr, VecDeque<i64>>>,
+    offense_counts: RwLock<HashMap<IpAddr, (usize, i64)>>,
+    calls_since_sweep: std::sync::atomic::AtomicU64,
+}
+
+impl AutoBanTracker {
+    pub fn new() -> Self {
+        Self {
+            events: RwLock::new(HashMap::new()),
+            offense_counts: RwLock::new(HashMap::new()),
+            calls_since_sweep: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    /// Records one failed/blocked request outcome for `ip` at `now` (unix seconds) and, if this
+    /// pushes the IP's event count within the trailing `window_secs` to `threshold` or beyond,
+    /// returns the ban duration (seconds) to apply, picked from `backoff_steps` by how many
+    /// times this IP has already been auto-banned (capped at the last step for repeat
+    /// offenders). Returns `None` while the IP stays under the threshold.
+    pub fn record_and_check(
+        &self,
+        ip: IpAddr,
+        now: i64,
+        threshold: u32,
+        window_secs: i64,
+        backoff_steps: &[i64],
+    ) -> Option<i64> {
+        self.maybe_sweep(now, window_secs);
+
+        let event_count = {
+            let mut events = self.events.write().unwrap();
+            let window = events.entry(ip).or_insert_with(VecDeque::new);
+            window.push_back(now);
+            while let Some(&oldest) = window.front() {
+                if now - oldest > window_secs {
+                    window.pop_front();
+                } else {
+                    break;
+                }
+            }
+            window.len() as u32
+        };
+
+        if event_count < threshold {
+            return None;
+        }
+
+        // Threshold breached: the IP is about to be blacklisted, so clear its window rather
+        // than re-triggering on every subsequent request while the ban entry propagates.
+        self.events.write().unwrap().remove(&ip);
+
+        let mut offense_counts = self.offense_counts.write().unwrap();
+        let entry = offense_counts.entry(ip).or_insert((0, now));
+        let step = backoff_steps
+            .get(entry.0)
+            .or_else(|| backoff_steps.last())
+            .copied()
+            .unwrap_or(3600);
+        entry.0 += 1;
+        entry.1 = now;
+        Some(step)
+    }
+
+    /// Opportunistically, every [`SWEEP_EVERY_N_CALLS`] calls, drops IPs whose sliding window
+    /// has emptied out (so an IP that fires once and never returns doesn't pin a `HashMap`
+    /// entry forever) and decays/evicts offense counters idle for longer than
+    /// [`OFFENSE_DECAY_SECS`]. Cheap on the common path: just an atomic increment until the
+    /// cadence is hit.
+    fn maybe_sweep(&self, now: i64, window_secs: i64) {
+        let calls = self
+            .calls_since_sweep
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1;
+        if calls < SWEEP_EVERY_N_CALLS {
+            return;
+        }
+        self.calls_since_sweep
+            .store(0, std::sync::atomic::Ordering::Relaxed);
+
+        self.events.write().unwrap().retain(|_, window| {
+            while let Some(&oldest) = window.front() {
+                if now - oldest > window_secs {
+                    window.pop_front();
+                } else {
+                    break;
+                }
+            }
+            !window.is_empty()
+        });
+
+        self.offense_counts
+            .write()
+            .unwrap()
+            .retain(|_, (_, last_seen)| now - *last_seen <= OFFENSE_DECAY_SECS);
+    }
+}
+
+impl Default for AutoBanTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_stays_quiet_below_threshold() {
+        let tracker = AutoBanTracker::new();
+        let addr = ip("203.0.113.1");
+        assert_eq!(tracker.record_and_check(addr, 0, 3, 60, &[60, 300]), None);
+        assert_eq!(tracker.record_and_check(addr, 1, 3, 60, &[60, 300]), None);
+    }
+
+    #[test]
+    fn test_trips_at_threshold() {
+        let tracker = AutoBanTracker::new();
+        let addr = ip("203.0.113.2");
+        assert_eq!(tracker.record_and_check(addr, 0, 3, 60, &[60, 300]), None);
+        assert_eq!(tracker.record_and_check(addr, 1, 3, 60, &[60, 300]), None);
+        assert_eq!(tracker.record_and_check(addr, 2, 3, 60, &[60, 300]), Some(60));
+    }
+
+    #[test]
+    fn test_events_outside_window_do_not_count() {
+        let tracker = AutoBanTracker::new();
+        let addr = ip("203.0.113.3");
+        assert_eq!(tracker.record_and_check(addr, 0, 3, 60, &[60, 300]), None);
+        assert_eq!(tracker.record_and_check(addr, 1, 3, 60, &[60, 300]), None);
+        // far outside the 60s window => the first two events should have expired
+        assert_eq!(tracker.record_and_check(addr, 1000, 3, 60, &[60, 300]), None);
+    }
+
+    #[test]
+    fn test_repeat_offenses_escalate_through_backoff_steps() {
+        let tracker = AutoBanTracker::new();
+        let addr = ip("203.0.113.4");
+        let steps = [60, 300, 1800, 7200];
+
+        assert_eq!(tracker.record_and_check(addr, 0, 1, 60, &steps), Some(60));
+        assert_eq!(tracker.record_and_check(addr, 100, 1, 60, &steps), Some(300));
+        assert_eq!(tracker.record_and_check(addr, 200, 1, 60, &steps), Some(1800));
+        assert_eq!(tracker.record_and_check(addr, 300, 1, 60, &steps), Some(7200));
+        // repeat offenders stay capped at the last step rather than falling off the end
+        assert_eq!(tracker.record_and_check(addr, 400, 1, 60, &steps), Some(7200));
+    }
+
+    #[test]
+    fn test_tracks_ips_independently() {
+        let tracker = AutoBanTracker::new();
+        let a = ip("203.0.113.5");
+        let b = ip("203.0.113.6");
+        assert_eq!(tracker.record_and_check(a, 0, 1, 60, &[60]), Some(60));
+        assert_eq!(tracker.record_and_check(b, 0, 1, 60, &[60]), Some(60));
+    }
+
+    #[test]
+    fn test_idle_ip_windows_are_evicted_on_sweep() {
+        let tracker = AutoBanTracker::new();
+        let idle = ip("203.0.113.7");
+        // one event, then the IP never comes back => its window never empties on its own.
+        assert_eq!(tracker.record_and_check(idle, 0, 10, 60, &[60]), None);
+        assert_eq!(tracker.events.read().unwrap().len(), 1);
+
+        // drive the opportunistic sweep cadence with a different (spoofed-flood-style) IP far
+        // enough in the future that `idle`'s single event has long since aged out of the window.
+        let flood_base = 100_000;
+        for i in 0..SWEEP_EVERY_N_CALLS {
+            tracker.record_and_check(ip("203.0.113.200"), flood_base + i as i64, 1_000_000, 60, &[60]);
+        }
+
+        assert!(!tracker.events.read().unwrap().contains_key(&idle));
+    }
+
+    #[test]
+    fn test_offense_counts_decay_after_idle_period() {
+        let tracker = AutoBanTracker::new();
+        let addr = ip("203.0.113.8");
+        let steps = [60, 300, 1800];
+
+        assert_eq!(tracker.record_and_check(addr, 0, 1, 60, &steps), Some(60));
+
+        // drive the sweep cadence with a different IP, far enough past OFFENSE_DECAY_SECS that
+        // `addr`'s offense counter should be forgotten rather than escalating further.
+        let resume_at = OFFENSE_DECAY_SECS + 1_000;
+        for i in 0..SWEEP_EVERY_N_CALLS {
+            tracker.record_and_check(ip("203.0.113.201"), resume_at + i as i64, 1_000_000, 60, &[60]);
+        }
+
+        assert!(!tracker.offense_counts.read().unwrap().contains_key(&addr));
+        // offending again lands back on the first backoff step instead of the escalated one.
+        assert_eq!(
+            tracker.record_and_check(addr, resume_at + SWEEP_EVERY_N_CALLS as i64, 1, 60, &steps),
+            Some(60)
+        );
+    }
+}
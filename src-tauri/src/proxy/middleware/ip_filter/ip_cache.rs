@@ -0,0 +1,158 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+struct Entry<V> {
+    value: V,
+    generation: u64,
+    expires_at: Instant,
+    /// CLOCK reference bit: set on every hit, cleared (and given a second chance) instead of
+    /// evicted on the first sweep, so frequently-hit keys survive longer than a plain FIFO.
+    referenced: AtomicBool,
+}
+
+/// Bounded, generation-invalidated cache modeled on the ClockPro algorithm used by the
+/// encrypted-dns-server blacklist subsystem: a CLOCK hand sweeps candidates for eviction,
+/// giving recently-referenced entries a second chance instead of evicting in pure insertion
+/// order. A global generation counter lets [`ClockCache::invalidate_all`] drop every cached
+/// verdict in O(1) whenever the backing table (whitelist/blacklist) is mutated, rather than
+/// having to walk and remove matching entries.
+pub struct ClockCache<K, V> {
+    capacity: usize,
+    ttl: Duration,
+    generation: AtomicU64,
+    entries: RwLock<HashMap<K, Entry<V>>>,
+    clock_hand: RwLock<VecDeque<K>>,
+}
+
+impl<K, V> ClockCache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            generation: AtomicU64::new(0),
+            entries: RwLock::new(HashMap::with_capacity(capacity.min(1024))),
+            clock_hand: RwLock::new(VecDeque::with_capacity(capacity.min(1024))),
+        }
+    }
+
+    /// Invalidates every cached entry by bumping the generation counter. Entries are left in
+    /// place (and get lazily evicted by [`Self::get`] / the CLOCK sweep) rather than cleared
+    /// immediately, avoiding a write-lock-and-walk on every table mutation.
+    pub fn invalidate_all(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.read().unwrap();
+        let entry = entries.get(key)?;
+        if entry.generation != self.generation.load(Ordering::SeqCst) {
+            return None;
+        }
+        if Instant::now() >= entry.expires_at {
+            return None;
+        }
+        entry.referenced.store(true, Ordering::Relaxed);
+        Some(entry.value.clone())
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.write().unwrap();
+        let mut clock_hand = self.clock_hand.write().unwrap();
+
+        if !entries.contains_key(&key) && entries.len() >= self.capacity {
+            Self::evict_one(&mut entries, &mut clock_hand);
+        }
+        if !entries.contains_key(&key) {
+            clock_hand.push_back(key.clone());
+        }
+        entries.insert(
+            key,
+            Entry {
+                value,
+                generation: self.generation.load(Ordering::SeqCst),
+                expires_at: Instant::now() + self.ttl,
+                referenced: AtomicBool::new(false),
+            },
+        );
+    }
+
+    /// One CLOCK sweep: referenced entries get their bit cleared and are recycled to the back
+    /// of the hand instead of being evicted, approximating ClockPro's cold/hot promotion.
+    fn evict_one(entries: &mut HashMap<K, Entry<V>>, clock_hand: &mut VecDeque<K>) {
+        for _ in 0..clock_hand.len() {
+            let Some(candidate) = clock_hand.pop_front() else {
+                return;
+            };
+            let referenced = entries
+                .get(&candidate)
+                .map(|e| e.referenced.swap(false, Ordering::Relaxed))
+                .unwrap_or(false);
+            if referenced {
+                clock_hand.push_back(candidate);
+                continue;
+            }
+            entries.remove(&candidate);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_get_roundtrip() {
+        let cache: ClockCache<&str, i32> = ClockCache::new(10, Duration::from_secs(30));
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"missing"), None);
+    }
+
+    #[test]
+    fn test_invalidate_all_drops_cached_entries() {
+        let cache: ClockCache<&str, i32> = ClockCache::new(10, Duration::from_secs(30));
+        cache.insert("a", 1);
+        cache.invalidate_all();
+        assert_eq!(cache.get(&"a"), None);
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let cache: ClockCache<&str, i32> = ClockCache::new(10, Duration::from_millis(1));
+        cache.insert("a", 1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get(&"a"), None);
+    }
+
+    #[test]
+    fn test_eviction_respects_reference_bit() {
+        let cache: ClockCache<i32, i32> = ClockCache::new(2, Duration::from_secs(30));
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        // Touch key 1 so it gets a second chance over key 2 on the next eviction sweep.
+        assert_eq!(cache.get(&1), Some(1));
+        cache.insert(3, 3);
+
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&3), Some(3));
+    }
+
+    #[test]
+    fn test_capacity_is_enforced() {
+        let cache: ClockCache<i32, i32> = ClockCache::new(3, Duration::from_secs(30));
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        let present = (0..10).filter(|i| cache.get(i).is_some()).count();
+        assert_eq!(present, 3);
+    }
+}
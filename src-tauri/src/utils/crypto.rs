@@ -1,17 +1,278 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
-    Aes256Gcm, AeadCore, Nonce,
+    aead::{rand_core::RngCore, Aead as AeadExt, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
 };
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{engine::general_purpose, Engine as _};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use serde::{Deserialize, Deserializer, Serializer};
 use sha2::Digest;
+use std::sync::RwLock;
 
 const FIXED_NONCE: &[u8; 12] = b"antigravsalt";
 const ENCRYPTED_PREFIX: &str = "ag_enc_";
 const ENCRYPTED_V2_PREFIX: &str = "ag_enc_v2_";
+const ENCRYPTED_V3_PREFIX: &str = "ag_enc_v3_";
+const ENCRYPTED_X_PREFIX: &str = "ag_enc_x_";
 
-/// 生成加密密钥 (基于设备 ID)
+/// Internal AEAD backend abstraction. `encrypt_v3`/`decrypt_v3_internal` code against this
+/// trait instead of a concrete cipher crate, so the backend can be swapped at compile time
+/// (see [`active_cipher`]) without touching the v3 wire format.
+trait Aead {
+    /// Required nonce length in bytes for this backend.
+    fn nonce_len(&self) -> usize;
+    fn seal(&self, nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String>;
+    fn open(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+struct AesGcmCipher(Aes256Gcm);
+
+impl AesGcmCipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self(Aes256Gcm::new(key.into()))
+    }
+}
+
+impl Aead for AesGcmCipher {
+    fn nonce_len(&self) -> usize {
+        12
+    }
+
+    fn seal(&self, nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        self.0
+            .encrypt(Nonce::from_slice(nonce), plaintext)
+            .map_err(|e| format!("Encryption failed: {}", e))
+    }
+
+    fn open(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+        self.0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|e| format!("Decryption failed: {}", e))
+    }
+}
+
+/// `ring`-backed AES-256-GCM implementation, selected instead of [`AesGcmCipher`] when the
+/// `ring-cipher` Cargo feature is enabled. Same wire format (12-byte nonce) either way.
+#[cfg(feature = "ring-cipher")]
+struct RingCipher(ring::aead::LessSafeKey);
+
+#[cfg(feature = "ring-cipher")]
+impl RingCipher {
+    fn new(key: &[u8; 32]) -> Self {
+        let unbound = ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, key)
+            .expect("AES-256-GCM key is always 32 bytes");
+        Self(ring::aead::LessSafeKey::new(unbound))
+    }
+}
+
+#[cfg(feature = "ring-cipher")]
+impl Aead for RingCipher {
+    fn nonce_len(&self) -> usize {
+        12
+    }
+
+    fn seal(&self, nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = ring::aead::Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|e| format!("Invalid nonce: {:?}", e))?;
+        let mut in_out = plaintext.to_vec();
+        self.0
+            .seal_in_place_append_tag(nonce, ring::aead::Aad::empty(), &mut in_out)
+            .map_err(|e| format!("Encryption failed: {:?}", e))?;
+        Ok(in_out)
+    }
+
+    fn open(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = ring::aead::Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|e| format!("Invalid nonce: {:?}", e))?;
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = self
+            .0
+            .open_in_place(nonce, ring::aead::Aad::empty(), &mut in_out)
+            .map_err(|e| format!("Decryption failed: {:?}", e))?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+/// XChaCha20-Poly1305 backend behind the `ag_enc_x_` format: a 24-byte random nonce makes
+/// nonce reuse a non-issue even for installs that encrypt a very large number of secrets
+/// over their lifetime. Always compiled in — selected via [`CipherSuite::XChaCha20`], not a
+/// Cargo feature, since it's a format choice rather than a drop-in AES-GCM replacement.
+struct XChaChaCipher(XChaCha20Poly1305);
+
+impl XChaChaCipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self(XChaCha20Poly1305::new(key.into()))
+    }
+}
+
+impl Aead for XChaChaCipher {
+    fn nonce_len(&self) -> usize {
+        24
+    }
+
+    fn seal(&self, nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        self.0
+            .encrypt(XNonce::from_slice(nonce), plaintext)
+            .map_err(|e| format!("Encryption failed: {}", e))
+    }
+
+    fn open(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+        self.0
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|e| format!("Decryption failed: {}", e))
+    }
+}
+
+/// Backend used for the `ag_enc_v3_` format, chosen at compile time by the `ring-cipher`
+/// feature. Both implementations produce byte-compatible AES-256-GCM output, so the wire
+/// format and key-generation header are unaffected by which one is compiled in.
+#[cfg(not(feature = "ring-cipher"))]
+fn active_cipher(key: &[u8; 32]) -> Box<dyn Aead> {
+    Box::new(AesGcmCipher::new(key))
+}
+
+#[cfg(feature = "ring-cipher")]
+fn active_cipher(key: &[u8; 32]) -> Box<dyn Aead> {
+    Box::new(RingCipher::new(key))
+}
+
+fn random_nonce(len: usize) -> Vec<u8> {
+    let mut nonce = vec![0u8; len];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Which AEAD algorithm/format `encrypt_string` writes. `AesGcm` is the rotation-aware
+/// `ag_enc_v3_` format from [`rekey_all`]; `XChaCha20` trades the generation header for a
+/// wider nonce, for installs that would rather not think about nonce reuse at all.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CipherSuite {
+    AesGcm,
+    XChaCha20,
+}
+
+static CIPHER_SUITE: RwLock<CipherSuite> = RwLock::new(CipherSuite::AesGcm);
+
+/// Selects the format used by subsequent [`encrypt_string`] calls. Existing secrets in
+/// either format remain readable via [`decrypt_string`] regardless of this setting.
+pub fn set_cipher_suite(suite: CipherSuite) {
+    *CIPHER_SUITE.write().unwrap() = suite;
+}
+
+fn active_cipher_suite() -> CipherSuite {
+    *CIPHER_SUITE.read().unwrap()
+}
+
+/// Argon2id parameters for passphrase-derived keys: 64 MiB memory, 3 iterations, 1 lane.
+const ARGON2_MEM_COST_KIB: u32 = 64 * 1024;
+const ARGON2_ITERATIONS: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
+
+/// Key-derivation mode selected by the user. Device-ID mode is the historical default;
+/// passphrase mode lets a secret survive a machine-ID change (VM clone, OS reinstall, ...).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyMode {
+    DeviceId,
+    Passphrase,
+}
+
+/// Active key configuration. `generation` is bumped by [`rekey_all`] every time the
+/// passphrase (and therefore the derived key) changes, and is embedded in the v3 payload
+/// header so stale secrets can be recognized and migrated lazily. `derived_key` caches the
+/// Argon2id output so passphrase mode pays the 64 MiB/3-iteration hash once per process
+/// instead of once per `encrypt_string`/`decrypt_string` call; it is always reset to `None`
+/// whenever the passphrase/generation changes (see [`use_passphrase_key`]).
+#[derive(Clone)]
+struct KeyConfig {
+    mode: KeyMode,
+    passphrase: Option<String>,
+    /// Per-install random salt used for Argon2id. Caller is responsible for persisting it.
+    salt: [u8; 16],
+    generation: u32,
+    derived_key: Option<[u8; 32]>,
+}
+
+impl Default for KeyConfig {
+    fn default() -> Self {
+        Self {
+            mode: KeyMode::DeviceId,
+            passphrase: None,
+            salt: [0u8; 16],
+            generation: 0,
+            derived_key: None,
+        }
+    }
+}
+
+static KEY_CONFIG: RwLock<Option<KeyConfig>> = RwLock::new(None);
+
+fn with_key_config<R>(f: impl FnOnce(&KeyConfig) -> R) -> R {
+    let guard = KEY_CONFIG.read().unwrap();
+    match guard.as_ref() {
+        Some(cfg) => f(cfg),
+        None => f(&KeyConfig::default()),
+    }
+}
+
+/// Switches to device-ID mode (the historical default): the key is derived from
+/// `machine_uid::get()` and there is no meaningful "generation" to rotate.
+pub fn use_device_id_key() {
+    let mut guard = KEY_CONFIG.write().unwrap();
+    *guard = Some(KeyConfig {
+        mode: KeyMode::DeviceId,
+        passphrase: None,
+        salt: [0u8; 16],
+        generation: 0,
+        derived_key: None,
+    });
+}
+
+/// Switches to passphrase mode with the given per-install `salt`. `generation` should be
+/// loaded from config (0 for a brand-new install) so that secrets encrypted under a
+/// previous passphrase are still recognized as stale and rekeyed via [`rekey_all`].
+///
+/// Note this only updates the *live* key used for new encrypt/decrypt calls — it does not by
+/// itself migrate already-stored secrets. [`rekey_all`] needs the *old* key to do that, and
+/// the old key can only be derived from the old passphrase (nothing about it is persisted
+/// beyond the salt). The caller driving a rotation is responsible for deriving/holding the
+/// old key (e.g. from a passphrase the user re-enters, or the in-memory key from before this
+/// call) and passing it to [`rekey_all`] itself; this module has no way to recover it later.
+pub fn use_passphrase_key(passphrase: &str, salt: [u8; 16], generation: u32) {
+    let mut guard = KEY_CONFIG.write().unwrap();
+    *guard = Some(KeyConfig {
+        mode: KeyMode::Passphrase,
+        passphrase: Some(passphrase.to_string()),
+        salt,
+        generation,
+        derived_key: None,
+    });
+}
+
+/// Current key generation, as embedded in newly written v3 payloads.
+pub fn current_key_generation() -> u32 {
+    with_key_config(|cfg| cfg.generation)
+}
+
+fn derive_passphrase_key(passphrase: &str, salt: &[u8; 16]) -> Result<[u8; 32], String> {
+    let params = Params::new(ARGON2_MEM_COST_KIB, ARGON2_ITERATIONS, ARGON2_PARALLELISM, Some(32))
+        .map_err(|e| format!("Invalid Argon2 params: {}", e))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut key = [0u8; 32];
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+/// 生成加密密钥 (基于设备 ID，或用户配置的主密码)
+///
+/// Resolver: picks device-ID mode or passphrase mode based on the active [`KeyConfig`].
 fn get_encryption_key() -> [u8; 32] {
+    get_encryption_key_for_generation(current_key_generation()).unwrap_or_else(|_| device_id_key())
+}
+
+fn device_id_key() -> [u8; 32] {
     // 使用设备唯一标识生成密钥
     let device_id = machine_uid::get().unwrap_or_else(|_| "default".to_string());
     let mut key = [0u8; 32];
@@ -20,12 +281,68 @@ fn get_encryption_key() -> [u8; 32] {
     key
 }
 
+/// Resolves the key active for `generation`. Only the *current* generation's passphrase is
+/// known at runtime, so this just validates the caller is asking for the live key; a
+/// mismatched generation is the signal [`rekey_all`] uses to know a secret needs migrating.
+///
+/// In passphrase mode the derived key is cached on [`KeyConfig`] so the Argon2id hash (64
+/// MiB / 3 iterations) runs once per process rather than once per `encrypt_string`/
+/// `decrypt_string` call; the cache is invalidated automatically whenever
+/// [`use_passphrase_key`] installs a new `KeyConfig`.
+fn get_encryption_key_for_generation(generation: u32) -> Result<[u8; 32], String> {
+    {
+        let guard = KEY_CONFIG.read().unwrap();
+        let cfg = guard.as_ref();
+        match cfg.map(|cfg| cfg.mode).unwrap_or(KeyMode::DeviceId) {
+            KeyMode::DeviceId => return Ok(device_id_key()),
+            KeyMode::Passphrase => {
+                let cfg = cfg.unwrap();
+                if generation != cfg.generation {
+                    return Err(format!(
+                        "requested generation {} does not match active generation {}",
+                        generation, cfg.generation
+                    ));
+                }
+                if let Some(key) = cfg.derived_key {
+                    return Ok(key);
+                }
+            }
+        }
+    }
+
+    // Cache miss: derive under a write lock and cache the result for subsequent calls.
+    let mut guard = KEY_CONFIG.write().unwrap();
+    let cfg = guard
+        .as_mut()
+        .ok_or_else(|| "passphrase mode active but no key config set".to_string())?;
+    if generation != cfg.generation {
+        return Err(format!(
+            "requested generation {} does not match active generation {}",
+            generation, cfg.generation
+        ));
+    }
+    if let Some(key) = cfg.derived_key {
+        return Ok(key);
+    }
+    let passphrase = cfg
+        .passphrase
+        .as_deref()
+        .ok_or_else(|| "passphrase mode active but no passphrase set".to_string())?;
+    let key = derive_passphrase_key(passphrase, &cfg.salt)?;
+    cfg.derived_key = Some(key);
+    Ok(key)
+}
+
 pub fn serialize_password<S>(password: &str, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
     // Prevent double-encryption: check for magic prefixes
-    if password.starts_with(ENCRYPTED_V2_PREFIX) || password.starts_with(ENCRYPTED_PREFIX) {
+    if password.starts_with(ENCRYPTED_X_PREFIX)
+        || password.starts_with(ENCRYPTED_V3_PREFIX)
+        || password.starts_with(ENCRYPTED_V2_PREFIX)
+        || password.starts_with(ENCRYPTED_PREFIX)
+    {
         return serializer.serialize_str(password);
     }
 
@@ -42,8 +359,24 @@ where
         return Ok(raw);
     }
 
+    // x format: ag_enc_x_{base64(nonce(24) || ciphertext)} — XChaCha20-Poly1305
+    if raw.starts_with(ENCRYPTED_X_PREFIX) {
+        let payload = &raw[ENCRYPTED_X_PREFIX.len()..];
+        match decrypt_x_internal(payload) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => Ok(raw), // Decryption failed (key changed), return raw to prevent data loss
+        }
+    }
+    // v3 format: ag_enc_v3_{base64(gen(4) || nonce(12) || ciphertext)}
+    else if raw.starts_with(ENCRYPTED_V3_PREFIX) {
+        let payload = &raw[ENCRYPTED_V3_PREFIX.len()..];
+        match decrypt_v3_internal(payload) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => Ok(raw), // Decryption failed (key changed), return raw to prevent data loss
+        }
+    }
     // v2 format: ag_enc_v2_{base64(nonce || ciphertext)}
-    if raw.starts_with(ENCRYPTED_V2_PREFIX) {
+    else if raw.starts_with(ENCRYPTED_V2_PREFIX) {
         let payload = &raw[ENCRYPTED_V2_PREFIX.len()..];
         match decrypt_v2_internal(payload) {
             Ok(plaintext) => Ok(plaintext),
@@ -66,25 +399,53 @@ where
     }
 }
 
-/// Encrypt using v2 format with random nonce
+/// Encrypts using whichever format the active [`CipherSuite`] advertises.
 pub fn encrypt_string(password: &str) -> Result<String, String> {
+    match active_cipher_suite() {
+        CipherSuite::AesGcm => encrypt_v3(password),
+        CipherSuite::XChaCha20 => encrypt_x(password),
+    }
+}
+
+/// v3 format: random nonce plus an embedded key-generation id, so secrets written under one
+/// passphrase generation can be recognized and rekeyed after rotation.
+fn encrypt_v3(password: &str) -> Result<String, String> {
+    let generation = current_key_generation();
     let key = get_encryption_key();
-    let cipher = Aes256Gcm::new(&key.into());
+    let backend = active_cipher(&key);
 
-    // Generate random 12-byte nonce
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let nonce = random_nonce(backend.nonce_len());
+    let ciphertext = backend.seal(&nonce, password.as_bytes())?;
 
-    let ciphertext = cipher
-        .encrypt(&nonce, password.as_bytes())
-        .map_err(|e| format!("Encryption failed: {}", e))?;
+    // gen(4) || nonce(12) || ciphertext
+    let mut combined = Vec::with_capacity(4 + nonce.len() + ciphertext.len());
+    combined.extend_from_slice(&generation.to_be_bytes());
+    combined.extend_from_slice(&nonce);
+    combined.extend_from_slice(&ciphertext);
+
+    let base64_encoded = general_purpose::STANDARD.encode(&combined);
+    Ok(format!("{}{}", ENCRYPTED_V3_PREFIX, base64_encoded))
+}
+
+/// x format: wide 24-byte nonce (reuse is never a practical concern) plus the same
+/// key-generation header as v3, so XChaCha secrets participate in [`rekey_all`] exactly like
+/// AES-GCM ones instead of being silently orphaned by a passphrase rotation.
+fn encrypt_x(password: &str) -> Result<String, String> {
+    let generation = current_key_generation();
+    let key = get_encryption_key();
+    let backend = XChaChaCipher::new(&key);
+
+    let nonce = random_nonce(backend.nonce_len());
+    let ciphertext = backend.seal(&nonce, password.as_bytes())?;
 
-    // Prepend nonce to ciphertext: nonce(12 bytes) || ciphertext
-    let mut combined = Vec::with_capacity(12 + ciphertext.len());
+    // gen(4) || nonce(24) || ciphertext
+    let mut combined = Vec::with_capacity(4 + nonce.len() + ciphertext.len());
+    combined.extend_from_slice(&generation.to_be_bytes());
     combined.extend_from_slice(&nonce);
     combined.extend_from_slice(&ciphertext);
 
     let base64_encoded = general_purpose::STANDARD.encode(&combined);
-    Ok(format!("{}{}", ENCRYPTED_V2_PREFIX, base64_encoded))
+    Ok(format!("{}{}", ENCRYPTED_X_PREFIX, base64_encoded))
 }
 
 /// Decrypt v2 format: base64 payload = nonce(12) || ciphertext
@@ -110,6 +471,46 @@ fn decrypt_v2_internal(encrypted_base64: &str) -> Result<String, String> {
     String::from_utf8(plaintext).map_err(|e| format!("UTF-8 conversion failed: {}", e))
 }
 
+/// Decrypt v3 format: base64 payload = gen(4) || nonce(12) || ciphertext
+fn decrypt_v3_internal(encrypted_base64: &str) -> Result<String, String> {
+    let combined = general_purpose::STANDARD
+        .decode(encrypted_base64)
+        .map_err(|e| format!("Base64 decode failed: {}", e))?;
+
+    if combined.len() < 4 + 12 + 1 {
+        return Err("Ciphertext too short (missing generation/nonce)".to_string());
+    }
+
+    let (gen_bytes, rest) = combined.split_at(4);
+    let generation = u32::from_be_bytes(gen_bytes.try_into().unwrap());
+    let (nonce_bytes, ciphertext) = rest.split_at(12);
+
+    let key = get_encryption_key_for_generation(generation)?;
+    let plaintext = active_cipher(&key).open(nonce_bytes, ciphertext)?;
+
+    String::from_utf8(plaintext).map_err(|e| format!("UTF-8 conversion failed: {}", e))
+}
+
+/// Decrypt x format: base64 payload = gen(4) || nonce(24) || ciphertext (XChaCha20-Poly1305)
+fn decrypt_x_internal(encrypted_base64: &str) -> Result<String, String> {
+    let combined = general_purpose::STANDARD
+        .decode(encrypted_base64)
+        .map_err(|e| format!("Base64 decode failed: {}", e))?;
+
+    if combined.len() < 4 + 24 + 1 {
+        return Err("Ciphertext too short (missing generation/nonce)".to_string());
+    }
+
+    let (gen_bytes, rest) = combined.split_at(4);
+    let generation = u32::from_be_bytes(gen_bytes.try_into().unwrap());
+    let (nonce_bytes, ciphertext) = rest.split_at(24);
+
+    let key = get_encryption_key_for_generation(generation)?;
+    let plaintext = XChaChaCipher::new(&key).open(nonce_bytes, ciphertext)?;
+
+    String::from_utf8(plaintext).map_err(|e| format!("UTF-8 conversion failed: {}", e))
+}
+
 /// Decrypt v1 format: fixed nonce, base64 payload = ciphertext only
 fn decrypt_v1_internal(encrypted_base64: &str) -> Result<String, String> {
     let key = get_encryption_key();
@@ -128,7 +529,11 @@ fn decrypt_v1_internal(encrypted_base64: &str) -> Result<String, String> {
 }
 
 pub fn decrypt_string(encrypted: &str) -> Result<String, String> {
-    if encrypted.starts_with(ENCRYPTED_V2_PREFIX) {
+    if encrypted.starts_with(ENCRYPTED_X_PREFIX) {
+        decrypt_x_internal(&encrypted[ENCRYPTED_X_PREFIX.len()..])
+    } else if encrypted.starts_with(ENCRYPTED_V3_PREFIX) {
+        decrypt_v3_internal(&encrypted[ENCRYPTED_V3_PREFIX.len()..])
+    } else if encrypted.starts_with(ENCRYPTED_V2_PREFIX) {
         decrypt_v2_internal(&encrypted[ENCRYPTED_V2_PREFIX.len()..])
     } else if encrypted.starts_with(ENCRYPTED_PREFIX) {
         decrypt_v1_internal(&encrypted[ENCRYPTED_PREFIX.len()..])
@@ -137,16 +542,131 @@ pub fn decrypt_string(encrypted: &str) -> Result<String, String> {
     }
 }
 
+/// Re-encrypts `entries` (any v1/v2/v3 secret) from `old_key`/`old_generation` to
+/// `new_key`/`new_generation`, producing fresh v3 payloads. Called on startup whenever the
+/// active key generation no longer matches a stored secret's embedded generation, so
+/// rotating the master passphrase never silently loses data. Entries that fail to decrypt
+/// under `old_key` (e.g. already on `new_generation`, or corrupt) are passed through
+/// unchanged rather than aborting the whole batch.
+pub fn rekey_all(
+    entries: &[String],
+    old_key: &[u8; 32],
+    old_generation: u32,
+    new_key: &[u8; 32],
+    new_generation: u32,
+) -> Vec<String> {
+    entries
+        .iter()
+        .map(|entry| {
+            rekey_one(entry, old_key, old_generation, new_key, new_generation)
+                .unwrap_or_else(|_| entry.clone())
+        })
+        .collect()
+}
+
+fn rekey_one(
+    entry: &str,
+    old_key: &[u8; 32],
+    old_generation: u32,
+    new_key: &[u8; 32],
+    new_generation: u32,
+) -> Result<String, String> {
+    let plaintext = decrypt_with_key(entry, old_key, old_generation)?;
+    encrypt_with_key(&plaintext, new_key, new_generation)
+}
+
+/// Decrypts any v1/v2/v3/x payload using an explicit key, bypassing the global [`KeyConfig`].
+/// `generation` is only consulted (and must match) for the v3/x formats that carry one.
+fn decrypt_with_key(encrypted: &str, key: &[u8; 32], generation: u32) -> Result<String, String> {
+    if let Some(payload) = encrypted.strip_prefix(ENCRYPTED_X_PREFIX) {
+        let combined = general_purpose::STANDARD
+            .decode(payload)
+            .map_err(|e| format!("Base64 decode failed: {}", e))?;
+        if combined.len() < 4 + 24 + 1 {
+            return Err("Ciphertext too short (missing generation/nonce)".to_string());
+        }
+        let (gen_bytes, rest) = combined.split_at(4);
+        if u32::from_be_bytes(gen_bytes.try_into().unwrap()) != generation {
+            return Err("generation mismatch".to_string());
+        }
+        let (nonce_bytes, ciphertext) = rest.split_at(24);
+        let plaintext = XChaChaCipher::new(key).open(nonce_bytes, ciphertext)?;
+        String::from_utf8(plaintext).map_err(|e| format!("UTF-8 conversion failed: {}", e))
+    } else if let Some(payload) = encrypted.strip_prefix(ENCRYPTED_V3_PREFIX) {
+        let combined = general_purpose::STANDARD
+            .decode(payload)
+            .map_err(|e| format!("Base64 decode failed: {}", e))?;
+        if combined.len() < 4 + 12 + 1 {
+            return Err("Ciphertext too short (missing generation/nonce)".to_string());
+        }
+        let (gen_bytes, rest) = combined.split_at(4);
+        if u32::from_be_bytes(gen_bytes.try_into().unwrap()) != generation {
+            return Err("generation mismatch".to_string());
+        }
+        let (nonce_bytes, ciphertext) = rest.split_at(12);
+        let plaintext = active_cipher(key).open(nonce_bytes, ciphertext)?;
+        String::from_utf8(plaintext).map_err(|e| format!("UTF-8 conversion failed: {}", e))
+    } else {
+        let cipher = Aes256Gcm::new(key.into());
+        if let Some(payload) = encrypted.strip_prefix(ENCRYPTED_V2_PREFIX) {
+            let combined = general_purpose::STANDARD
+                .decode(payload)
+                .map_err(|e| format!("Base64 decode failed: {}", e))?;
+            if combined.len() < 13 {
+                return Err("Ciphertext too short (missing nonce)".to_string());
+            }
+            let (nonce_bytes, ciphertext) = combined.split_at(12);
+            decrypt_with_cipher(&cipher, nonce_bytes, ciphertext)
+        } else {
+            // v1 (`ag_enc_` prefix) or bare legacy payload: fixed nonce.
+            let payload = encrypted.strip_prefix(ENCRYPTED_PREFIX).unwrap_or(encrypted);
+            let ciphertext = general_purpose::STANDARD
+                .decode(payload)
+                .map_err(|e| format!("Base64 decode failed: {}", e))?;
+            decrypt_with_cipher(&cipher, FIXED_NONCE, &ciphertext)
+        }
+    }
+}
+
+fn decrypt_with_cipher(cipher: &Aes256Gcm, nonce_bytes: &[u8], ciphertext: &[u8]) -> Result<String, String> {
+    let nonce = Nonce::from_slice(nonce_bytes);
+    let plaintext = cipher
+        .decrypt(nonce, ciphertext)
+        .map_err(|e| format!("Decryption failed: {}", e))?;
+    String::from_utf8(plaintext).map_err(|e| format!("UTF-8 conversion failed: {}", e))
+}
+
+fn encrypt_with_key(plaintext: &str, key: &[u8; 32], generation: u32) -> Result<String, String> {
+    let backend = active_cipher(key);
+    let nonce = random_nonce(backend.nonce_len());
+    let ciphertext = backend.seal(&nonce, plaintext.as_bytes())?;
+
+    let mut combined = Vec::with_capacity(4 + nonce.len() + ciphertext.len());
+    combined.extend_from_slice(&generation.to_be_bytes());
+    combined.extend_from_slice(&nonce);
+    combined.extend_from_slice(&ciphertext);
+
+    Ok(format!(
+        "{}{}",
+        ENCRYPTED_V3_PREFIX,
+        general_purpose::STANDARD.encode(&combined)
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use aes_gcm::AeadCore;
 
     #[test]
-    fn test_v2_encrypt_decrypt_cycle() {
+    fn test_v3_encrypt_decrypt_cycle() {
+        // Calls encrypt_v3 directly (rather than encrypt_string, which depends on the global
+        // CIPHER_SUITE) so this test's format assertion can't race test_cipher_suite_selects_*
+        // flipping the suite from another test thread.
         let password = "my_secret_password";
-        let encrypted = encrypt_string(password).unwrap();
+        let encrypted = encrypt_v3(password).unwrap();
 
-        assert!(encrypted.starts_with(ENCRYPTED_V2_PREFIX));
+        assert!(encrypted.starts_with(ENCRYPTED_V3_PREFIX));
         assert_ne!(password, encrypted);
 
         let decrypted = decrypt_string(&encrypted).unwrap();
@@ -154,10 +674,10 @@ mod tests {
     }
 
     #[test]
-    fn test_v2_unique_nonces() {
+    fn test_v3_unique_nonces() {
         let password = "same_password";
-        let enc1 = encrypt_string(password).unwrap();
-        let enc2 = encrypt_string(password).unwrap();
+        let enc1 = encrypt_v3(password).unwrap();
+        let enc2 = encrypt_v3(password).unwrap();
         // Same plaintext should produce different ciphertexts (random nonce)
         assert_ne!(enc1, enc2);
         // But both should decrypt to the same value
@@ -165,6 +685,44 @@ mod tests {
         assert_eq!(decrypt_string(&enc2).unwrap(), password);
     }
 
+    #[test]
+    fn test_x_encrypt_decrypt_cycle() {
+        let password = "xchacha_password";
+        let encrypted = encrypt_x(password).unwrap();
+
+        assert!(encrypted.starts_with(ENCRYPTED_X_PREFIX));
+        let decrypted = decrypt_string(&encrypted).unwrap();
+        assert_eq!(password, decrypted);
+    }
+
+    #[test]
+    fn test_cipher_suite_selects_encrypt_string_format() {
+        set_cipher_suite(CipherSuite::XChaCha20);
+        let encrypted = encrypt_string("suite_selected").unwrap();
+        set_cipher_suite(CipherSuite::AesGcm);
+
+        assert!(encrypted.starts_with(ENCRYPTED_X_PREFIX));
+        assert_eq!(decrypt_string(&encrypted).unwrap(), "suite_selected");
+    }
+
+    #[test]
+    fn test_v2_backward_compatibility() {
+        // Simulate v2 encryption (random nonce, ag_enc_v2_ prefix)
+        let password = "v2_password";
+        let key = get_encryption_key();
+        let cipher = Aes256Gcm::new(&key.into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, password.as_bytes()).unwrap();
+        let mut combined = Vec::with_capacity(12 + ciphertext.len());
+        combined.extend_from_slice(&nonce);
+        combined.extend_from_slice(&ciphertext);
+        let v2_encrypted = format!("{}{}", ENCRYPTED_V2_PREFIX, general_purpose::STANDARD.encode(&combined));
+
+        // v2 format should still decrypt correctly even though encrypt_string now writes v3
+        let decrypted = decrypt_string(&v2_encrypted).unwrap();
+        assert_eq!(password, decrypted);
+    }
+
     #[test]
     fn test_v1_backward_compatibility() {
         // Simulate v1 encryption (fixed nonce, ag_enc_ prefix)
@@ -194,4 +752,63 @@ mod tests {
         let decrypted = decrypt_string(&bare_encrypted).unwrap();
         assert_eq!(password, decrypted);
     }
+
+    #[test]
+    fn test_rekey_all_migrates_generation() {
+        let old_key = [1u8; 32];
+        let new_key = [2u8; 32];
+        let old = encrypt_with_key("rotate_me", &old_key, 1).unwrap();
+
+        let migrated = rekey_all(&[old.clone()], &old_key, 1, &new_key, 2);
+
+        assert_eq!(migrated.len(), 1);
+        assert_ne!(migrated[0], old);
+        assert_eq!(decrypt_with_key(&migrated[0], &new_key, 2).unwrap(), "rotate_me");
+        // Old key/generation can no longer decrypt the migrated payload.
+        assert!(decrypt_with_key(&migrated[0], &old_key, 1).is_err());
+    }
+
+    #[test]
+    fn test_rekey_all_migrates_x_format_generation() {
+        // The ag_enc_x_ format used to have no generation header at all, so rekey_all would
+        // pass it through unchanged on rotation and silently lose the secret.
+        let old_key = [5u8; 32];
+        let new_key = [6u8; 32];
+        let backend = XChaChaCipher::new(&old_key);
+        let nonce = vec![0u8; backend.nonce_len()];
+        let ciphertext = backend.seal(&nonce, b"rotate_me_x").unwrap();
+        let mut combined = Vec::with_capacity(4 + nonce.len() + ciphertext.len());
+        combined.extend_from_slice(&1u32.to_be_bytes());
+        combined.extend_from_slice(&nonce);
+        combined.extend_from_slice(&ciphertext);
+        let old = format!("{}{}", ENCRYPTED_X_PREFIX, general_purpose::STANDARD.encode(&combined));
+
+        let migrated = rekey_all(&[old.clone()], &old_key, 1, &new_key, 2);
+
+        assert_eq!(migrated.len(), 1);
+        assert_ne!(migrated[0], old);
+        assert_eq!(decrypt_with_key(&migrated[0], &new_key, 2).unwrap(), "rotate_me_x");
+    }
+
+    #[test]
+    fn test_rekey_all_passes_through_undecryptable_entries() {
+        let old_key = [3u8; 32];
+        let new_key = [4u8; 32];
+        let garbage = "not a valid payload".to_string();
+
+        let migrated = rekey_all(&[garbage.clone()], &old_key, 1, &new_key, 2);
+
+        assert_eq!(migrated, vec![garbage]);
+    }
+
+    #[test]
+    fn test_passphrase_key_derivation_is_deterministic() {
+        let salt = [7u8; 16];
+        let key1 = derive_passphrase_key("correct horse battery staple", &salt).unwrap();
+        let key2 = derive_passphrase_key("correct horse battery staple", &salt).unwrap();
+        assert_eq!(key1, key2);
+
+        let key3 = derive_passphrase_key("different passphrase", &salt).unwrap();
+        assert_ne!(key1, key3);
+    }
 }